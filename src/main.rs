@@ -1,11 +1,17 @@
 mod wayland_window;
 
-use wayland_window::PinEntryWindow;
-use pinentry::{Buttons, ConfirmChoice, PinentryCmds, PinentryServer};
+use wayland_window::{Dismissed, PinEntryWindow, QualityMeter};
+use calloop::EventLoop;
+use pinentry::{Buttons, ConfirmChoice, PinentryCmds, PinentryServer, QualityBar};
+use assuan::transcript::Transcript;
+use std::fs::File;
 use std::io::{stdin, stdout};
-use std::sync::{Arc, Mutex};
+use std::os::fd::AsFd;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::path::PathBuf;
+use std::time::Duration;
+use smithay_client_toolkit::reexports::calloop_wayland_source::WaylandSource;
 
 struct WaylandPinentry {
     _tty: Option<PathBuf>,
@@ -22,6 +28,8 @@ impl WaylandPinentry {
         window_title: &str,
         desc: Option<&str>,
         prompt: &str,
+        timeout: Option<Duration>,
+        mut quality_bar: Option<QualityBar>,
     ) -> Result<Option<String>, PinentryError> {
         log::debug!("Creating Wayland window for PIN entry");
 
@@ -37,15 +45,37 @@ impl WaylandPinentry {
         let title = window_title.to_string();
         let prompt = prompt.to_string();
 
+        // Quality of the PIN is estimated by the client, so checks requested by the window are
+        // processed on this thread, and estimations are sent back to the window
+        let (checks_sender, checks) = mpsc::channel();
+        let (quality_sender, quality_updates) = calloop::channel::channel();
+        let quality_meter = quality_bar.as_ref().map(|bar| {
+            QualityMeter::new(
+                bar.label.to_string(),
+                bar.tooltip.map(str::to_string),
+                checks_sender,
+            )
+        });
+
         let wayland_thread = thread::spawn(move || {
-            let (mut app, _conn, mut event_queue) = PinEntryWindow::new(description, prompt, title);
+            let (mut app, conn, event_queue) =
+                PinEntryWindow::new(description, prompt, title, timeout, quality_meter);
+
+            let qh = event_queue.handle();
+            app.create_window(&qh);
 
-            app.create_window(&event_queue.handle());
+            let mut event_loop = EventLoop::<PinEntryWindow>::try_new()
+                .expect("Failed to create event loop");
+            WaylandSource::new(conn, event_queue)
+                .insert(event_loop.handle())
+                .expect("Failed to insert Wayland source");
+            app.schedule_timeout(&event_loop.handle());
+            app.schedule_quality_updates(&event_loop.handle(), quality_updates, qh);
 
             let app_result = app.get_result();
 
             loop {
-                event_queue.blocking_dispatch(&mut app).unwrap();
+                event_loop.dispatch(None, &mut app).unwrap();
                 log::debug!("An event has been handled");
 
                 if let Some(res) = app_result.lock().unwrap().take() {
@@ -55,13 +85,28 @@ impl WaylandPinentry {
             }
         });
 
+        // Window drops its sender once closed, which ends this loop
+        if let Some(quality_bar) = quality_bar.as_mut() {
+            for pin in checks.iter() {
+                // Only the latest PIN is worth estimating, skip the ones typed in the meantime
+                let pin = checks.try_iter().last().unwrap_or(pin);
+                match quality_bar.check(pin.as_str()) {
+                    Ok(quality) => {
+                        let _ = quality_sender.send(quality);
+                    }
+                    Err(e) => log::warn!("Failed to check PIN quality: {}", e),
+                }
+            }
+        }
+
         wayland_thread
             .join()
             .map_err(|_| PinentryError::ThreadPanic)?;
 
         match result.lock().unwrap().take() {
             Some(Ok(pin)) => Ok(Some(pin)),
-            Some(Err(_)) => Ok(None),
+            Some(Err(Dismissed::TimedOut)) => Err(PinentryError::Timeout),
+            Some(Err(Dismissed::Cancelled)) => Ok(None),
             None => Ok(None),
         }
     }
@@ -70,19 +115,24 @@ impl WaylandPinentry {
 #[derive(Debug)]
 enum PinentryError {
     ThreadPanic,
+    Timeout,
 }
 
 impl std::fmt::Display for PinentryError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::ThreadPanic => write!(f, "Wayland thread panicked"),
+            Self::Timeout => write!(f, "Timed out waiting for user input"),
         }
     }
 }
 
 impl pinentry::HasErrorCode for PinentryError {
     fn code(&self) -> assuan::ErrorCode {
-        assuan::ErrorCode::INTERNAL
+        match self {
+            Self::ThreadPanic => assuan::ErrorCode::INTERNAL,
+            Self::Timeout => assuan::ErrorCode::TIMEOUT,
+        }
     }
 }
 
@@ -94,17 +144,27 @@ impl PinentryCmds for WaylandPinentry {
         Ok(())
     }
 
+    fn flavor(&self) -> &str {
+        "wayland"
+    }
+
+    fn version(&self) -> &str {
+        env!("CARGO_PKG_VERSION")
+    }
+
     fn get_pin(
         &mut self,
         error: Option<&str>,
         window_title: &str,
         desc: Option<&str>,
         prompt: &str,
+        timeout: Option<Duration>,
+        quality_bar: Option<QualityBar>,
     ) -> Result<Option<pinentry::SecretData>, Self::Error> {
-        let pin = self.show_pin_dialog(error, window_title, desc, prompt)?;
+        let pin = self.show_pin_dialog(error, window_title, desc, prompt, timeout, quality_bar)?;
         Ok(pin.map(|p| {
             let mut secret_data = pinentry::SecretData::default();
-            secret_data.append(&p).expect("PIN should fit in response");
+            secret_data.append(&p);
             secret_data
         }))
     }
@@ -115,12 +175,15 @@ impl PinentryCmds for WaylandPinentry {
         window_title: &str,
         desc: Option<&str>,
         _buttons: Buttons,
+        timeout: Option<Duration>,
     ) -> Result<ConfirmChoice, Self::Error> {
         let result = self.show_pin_dialog(
             error,
             window_title,
             desc,
             "Press Enter to confirm, Escape to cancel",
+            timeout,
+            None,
         )?;
 
         if result.is_some() {
@@ -131,6 +194,29 @@ impl PinentryCmds for WaylandPinentry {
     }
 }
 
+/// How long to wait for the next request from the client
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// How long receiving a single line from the client may take
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+/// Enables the protocol transcript: `log` records it to the log with debug level, any other
+/// value is a path of the file the transcript is appended to
+const TRANSCRIPT_ENV: &str = "PINENTRY_WAYLAND_TRANSCRIPT";
+
+/// Sets up the protocol transcript if it's enabled via [`TRANSCRIPT_ENV`]
+fn transcript_from_env() -> Option<Transcript> {
+    let target = std::env::var_os(TRANSCRIPT_ENV)?;
+    if target == "log" {
+        return Some(Transcript::to_log());
+    }
+    match Transcript::to_file(&target) {
+        Ok(transcript) => Some(transcript),
+        Err(e) => {
+            log::warn!("Failed to open transcript file {:?}: {}", target, e);
+            None
+        }
+    }
+}
+
 fn main() {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
         .init();
@@ -138,11 +224,26 @@ fn main() {
     log::debug!("Pinentry Wayland starting");
 
     let pinentry = WaylandPinentry::new();
-    let server = PinentryServer::new(pinentry).build_assuan_server();
+    let mut server = PinentryServer::new(pinentry)
+        .build_assuan_server()
+        // Don't linger around if the client hangs
+        .idle_timeout(IDLE_TIMEOUT)
+        .read_timeout(READ_TIMEOUT);
+    // Protocol transcript is opt-in, secrets are redacted
+    if let Some(transcript) = transcript_from_env() {
+        server = server.transcript(transcript);
+    }
 
-    let mut server = server;
+    // `Stdin` buffers the data, so timeouts need reading from the file descriptor directly
+    let input = match stdin().as_fd().try_clone_to_owned() {
+        Ok(fd) => File::from(fd),
+        Err(e) => {
+            log::error!("Failed to open stdin: {}", e);
+            std::process::exit(1);
+        }
+    };
 
-    if let Err(e) = server.serve_client(stdin(), stdout()) {
+    if let Err(e) = server.serve_client_with_timeouts(input, stdout()) {
         log::error!("Error serving client: {}", e);
         std::process::exit(1);
     }
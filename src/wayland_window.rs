@@ -25,8 +25,15 @@ use smithay_client_toolkit::{
     },
     shm::{slot::SlotPool, Shm, ShmHandler},
 };
+use calloop::{
+    channel::{Channel, Event},
+    timer::{TimeoutAction, Timer},
+    LoopHandle,
+};
+use pinentry::SecretData;
 use std::io::Read;
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
 use wayland_client::{
     globals::registry_queue_init,
     protocol::{wl_keyboard, wl_output, wl_pointer, wl_seat, wl_surface},
@@ -60,6 +67,36 @@ fn load_system_font() -> Vec<u8> {
     panic!("No system font found. Please install DejaVu Sans or Liberation Sans fonts.");
 }
 
+/// Reason why the window was closed without the user submitting its input
+#[derive(Debug)]
+pub enum Dismissed {
+    Cancelled,
+    TimedOut,
+}
+
+/// Quality bar displayed under the PIN input
+///
+/// Every change of the PIN is sent over `checks` as [`SecretData`], so it's wiped once
+/// checked. Estimated quality is received back via the channel given to
+/// [`PinEntryWindow::schedule_quality_updates`].
+pub struct QualityMeter {
+    label: String,
+    tooltip: Option<String>,
+    quality: i32,
+    checks: mpsc::Sender<SecretData>,
+}
+
+impl QualityMeter {
+    pub fn new(label: String, tooltip: Option<String>, checks: mpsc::Sender<SecretData>) -> Self {
+        Self {
+            label,
+            tooltip,
+            quality: 0,
+            checks,
+        }
+    }
+}
+
 pub struct PinEntryWindow {
     registry_state: RegistryState,
     seat_state: SeatState,
@@ -79,7 +116,9 @@ pub struct PinEntryWindow {
     prompt: String,
     title: String,
     pin_input: String,
-    result: Arc<Mutex<Option<Result<String, String>>>>,
+    timeout: Option<Duration>,
+    quality_meter: Option<QualityMeter>,
+    result: Arc<Mutex<Option<Result<String, Dismissed>>>>,
     cursor_visible: bool,
     configured: bool,
     modifiers: Modifiers,
@@ -92,7 +131,13 @@ pub struct PinEntryWindow {
 }
 
 impl PinEntryWindow {
-    pub fn new(description: String, prompt: String, title: String) -> (Self, Connection, EventQueue<Self>) {
+    pub fn new(
+        description: String,
+        prompt: String,
+        title: String,
+        timeout: Option<Duration>,
+        quality_meter: Option<QualityMeter>,
+    ) -> (Self, Connection, EventQueue<Self>) {
         let conn = Connection::connect_to_env().expect("Failed to connect to Wayland");
         let (globals, event_queue) = registry_queue_init(&conn).expect("Failed to init registry");
         let qh = event_queue.handle();
@@ -126,6 +171,8 @@ impl PinEntryWindow {
             prompt,
             title,
             pin_input: String::new(),
+            timeout,
+            quality_meter,
             result: Arc::new(Mutex::new(None)),
             cursor_visible: true,
             configured: false,
@@ -162,6 +209,51 @@ impl PinEntryWindow {
         self.pool = Some(pool);
     }
 
+    /// Arms a timer that closes the window once the timeout given at construction elapses
+    pub fn schedule_timeout(&self, handle: &LoopHandle<'static, Self>) {
+        let Some(timeout) = self.timeout else {
+            return;
+        };
+
+        handle
+            .insert_source(Timer::from_duration(timeout), |_, _, app| {
+                log::debug!("PIN entry timed out");
+                *app.result.lock().unwrap() = Some(Err(Dismissed::TimedOut));
+                TimeoutAction::Drop
+            })
+            .expect("Failed to insert timeout timer");
+    }
+
+    /// Redraws the quality bar whenever a new estimation is received from `updates`
+    pub fn schedule_quality_updates(
+        &self,
+        handle: &LoopHandle<'static, Self>,
+        updates: Channel<i32>,
+        qh: QueueHandle<Self>,
+    ) {
+        if self.quality_meter.is_none() {
+            return;
+        }
+
+        handle
+            .insert_source(updates, move |event, _, app| {
+                if let (Event::Msg(quality), Some(meter)) = (event, app.quality_meter.as_mut()) {
+                    meter.quality = quality;
+                    app.draw(&qh);
+                }
+            })
+            .expect("Failed to insert quality updates source");
+    }
+
+    /// Asks for estimation of the PIN quality if the quality bar is displayed
+    fn request_quality_check(&self) {
+        if let Some(meter) = &self.quality_meter
+            && meter.checks.send(SecretData::new(&self.pin_input)).is_err()
+        {
+            log::debug!("Quality checks are no longer processed");
+        }
+    }
+
     pub fn draw(&mut self, _qh: &QueueHandle<Self>) {
         if !self.configured {
             return;
@@ -183,6 +275,10 @@ impl PinEntryWindow {
         let cursor_visible = self.cursor_visible;
         let description = self.description.clone();
         let prompt = self.prompt.clone();
+        let quality_bar = self
+            .quality_meter
+            .as_ref()
+            .map(|meter| (meter.label.clone(), meter.tooltip.clone(), meter.quality));
 
         let pool = match self.pool.as_mut() {
             Some(p) => p,
@@ -212,6 +308,9 @@ impl PinEntryWindow {
             cursor_visible,
             &description,
             &prompt,
+            quality_bar
+                .as_ref()
+                .map(|(label, tooltip, quality)| (label.as_str(), tooltip.as_deref(), *quality)),
         );
 
         window
@@ -232,6 +331,7 @@ impl PinEntryWindow {
         cursor_visible: bool,
         description: &str,
         prompt: &str,
+        quality_bar: Option<(&str, Option<&str>, i32)>,
     ) {
         let bg_color = 0xFF1E1E2Eu32;
         let text_area_color = 0xFF313244u32;
@@ -300,6 +400,36 @@ impl PinEntryWindow {
                 }
             }
         }
+
+        if let Some((label, tooltip, quality)) = quality_bar {
+            let bar_x = 100;
+            let bar_y = input_box_y + input_box_height + 8;
+            let bar_height = 10;
+            let bar_width = width.saturating_sub(bar_x + padding);
+            let filled_width = bar_width * quality.unsigned_abs().min(100) / 100;
+            // Negative quality means that the PIN is not acceptable at all
+            let bar_color = match quality {
+                ..=39 => 0xFFF38BA8u32,
+                40..=69 => 0xFFF9E2AFu32,
+                _ => 0xFFA6E3A1u32,
+            };
+
+            Self::draw_text_with_font(canvas, width, label, 20.0, (bar_y + bar_height) as f32, 12.0, label_color, font_data, shape_context, scale_context);
+
+            for y in bar_y..(bar_y + bar_height) {
+                for x in bar_x..(bar_x + bar_width) {
+                    let color = if x < bar_x + filled_width { bar_color } else { text_area_color };
+                    let offset = ((y * width + x) * 4) as usize;
+                    if offset + 4 <= canvas.len() {
+                        canvas[offset..offset + 4].copy_from_slice(&color.to_ne_bytes());
+                    }
+                }
+            }
+
+            if let Some(tooltip) = tooltip {
+                Self::draw_text_with_font(canvas, width, tooltip, 20.0, (bar_y + bar_height + 14) as f32, 10.0, label_color, font_data, shape_context, scale_context);
+            }
+        }
     }
 
     fn draw_text_with_font(
@@ -495,7 +625,7 @@ impl PinEntryWindow {
         }
     }
 
-    pub fn get_result(&self) -> Arc<Mutex<Option<Result<String, String>>>> {
+    pub fn get_result(&self) -> Arc<Mutex<Option<Result<String, Dismissed>>>> {
         Arc::clone(&self.result)
     }
 }
@@ -580,7 +710,7 @@ impl OutputHandler for PinEntryWindow {
 
 impl WindowHandler for PinEntryWindow {
     fn request_close(&mut self, _conn: &Connection, _qh: &QueueHandle<Self>, _window: &Window) {
-        *self.result.lock().unwrap() = Some(Err("User cancelled".to_string()));
+        *self.result.lock().unwrap() = Some(Err(Dismissed::Cancelled));
     }
 
     fn configure(
@@ -684,9 +814,10 @@ impl KeyboardHandler for PinEntryWindow {
         if keysym == Keysym::Return || keysym == Keysym::KP_Enter {
             *self.result.lock().unwrap() = Some(Ok(self.pin_input.clone()));
         } else if keysym == Keysym::Escape {
-            *self.result.lock().unwrap() = Some(Err("User cancelled".to_string()));
+            *self.result.lock().unwrap() = Some(Err(Dismissed::Cancelled));
         } else if keysym == Keysym::BackSpace {
             self.pin_input.pop();
+            self.request_quality_check();
             self.draw(qh);
         } else if ctrl_pressed && (keysym == Keysym::v || keysym == Keysym::V) {
             // Trigger paste from clipboard
@@ -695,6 +826,7 @@ impl KeyboardHandler for PinEntryWindow {
             if let Some(content) = clipboard_content {
                 log::debug!("Pasting {} characters from clipboard", content.len());
                 self.pin_input.push_str(&content);
+                self.request_quality_check();
                 self.draw(qh);
             } else if let Some(offer) = self.clipboard_offer.take() {
                 // Start reading clipboard asynchronously
@@ -711,6 +843,7 @@ impl KeyboardHandler for PinEntryWindow {
         } else if let Some(c) = keysym_to_char(keysym) {
             if c.is_ascii_alphanumeric() || c.is_ascii_punctuation() || c.is_ascii_whitespace() {
                 self.pin_input.push(c);
+                self.request_quality_check();
                 self.draw(qh);
             }
         }